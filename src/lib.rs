@@ -7,6 +7,7 @@
 #![forbid(unsafe_op_in_unsafe_fn)]
 
 use ltptr::ConstLtPtr;
+use ltptr::MutLtPtr;
 use ltptr::FromLtPtr as _;
 
 use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
@@ -15,12 +16,18 @@ use windows::Foundation::Uri;
 use windows::System::{Launcher, LauncherOptions};
 
 use std::ffi::CStr;
+use std::sync::RwLock;
+
+/// The operator-configured filter consulted by [`fc_open_uri`] before a URI is
+/// dispatched. `None` means no filter is installed and everything is allowed.
+static HANDLER_FILTER: RwLock<Option<HandlerFilter>> = RwLock::new(None);
 
 /// Opens the given C string-encoded URI, which must use a `web+*` scheme.
 ///
 /// The C string must be UTF-8, and must not be a null pointer.
 ///
-/// Returns 0 on failure, 1 on success.
+/// Returns 1 on success and 0 on failure. Returns -1 if the URI was rejected
+/// by the handler filter installed via [`fc_set_handler_filter`].
 #[no_mangle]
 pub unsafe extern "C" fn fc_open_uri(uri: ConstLtPtr<'_, std::ffi::c_char>) -> i32 {
     // SAFETY: guaranteed by API contract.
@@ -28,14 +35,129 @@ pub unsafe extern "C" fn fc_open_uri(uri: ConstLtPtr<'_, std::ffi::c_char>) -> i
         return 0
     };
 
-    match fc_open_uri_inner(uri) {
+    let fallback = match get_fallback(uri) {
+        Ok(fallback) => fallback,
+        Err(_) => return 0,
+    };
+
+    // consult the operator-configured filter, if any, before we hand anything
+    // to the launcher.
+    if !handler_is_allowed(&fallback.scheme, &fallback.authority) {
+        return -1;
+    }
+
+    match fc_open_uri_inner(uri, &fallback.url) {
+        Ok(_) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Like [`fc_open_uri`], but first verifies over the network that the target
+/// site actually implements the Fedi-To fallback before launching it.
+///
+/// Issues an HTTPS GET to the computed fallback URL (with the `Host` header set
+/// from the parsed authority so virtual-hosted instances resolve), following up
+/// to `max_redirects` redirects and giving up after `timeout_ms` milliseconds.
+/// Only a 2xx response with a non-empty body counts as "handler present".
+///
+/// Returns 1 on success and 0 on failure, -1 if the URI was rejected by the
+/// handler filter, and -2 if the pre-flight check failed (non-2xx, empty body,
+/// timeout, or a transport/TLS error) so the caller can surface its own UI
+/// instead of opening a broken page.
+#[no_mangle]
+pub unsafe extern "C" fn fc_open_uri_checked(
+    uri: ConstLtPtr<'_, std::ffi::c_char>,
+    timeout_ms: u64,
+    max_redirects: u32,
+) -> i32 {
+    // SAFETY: guaranteed by API contract.
+    let Ok(uri) = unsafe { CStr::from_lt_ptr(uri) }.to_str() else {
+        return 0
+    };
+
+    let fallback = match get_fallback(uri) {
+        Ok(fallback) => fallback,
+        Err(_) => return 0,
+    };
+
+    if !handler_is_allowed(&fallback.scheme, &fallback.authority) {
+        return -1;
+    }
+
+    // the rest of the crate is synchronous, so spin up a single-threaded
+    // runtime just to drive the pre-flight request to completion.
+    let Ok(runtime) = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    else {
+        return -2
+    };
+    match runtime.block_on(handler_is_present(&fallback, timeout_ms, max_redirects)) {
+        Ok(true) => {},
+        Ok(false) | Err(_) => return -2,
+    }
+
+    match fc_open_uri_inner(uri, &fallback.url) {
         Ok(_) => 1,
         Err(_) => 0,
     }
 }
 
-fn fc_open_uri_inner(uri: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let fallback = get_fallback(uri)?;
+/// Computes the `/.well-known/protocol-handler` fallback URL for the given
+/// C string-encoded `web+*` URI, without launching anything.
+///
+/// The C string must be UTF-8, and must not be a null pointer.
+///
+/// Uses a two-call convention: `out_len` must point to the capacity (in bytes,
+/// including the trailing NUL) of the `out` buffer. If `out` is too small, the
+/// required length is written to `*out_len` and 0 is returned; the caller
+/// should then allocate that many bytes and call again. On success the
+/// NUL-terminated fallback URL is written to `out`, `*out_len` is set to the
+/// number of bytes written (including the NUL), and 1 is returned.
+///
+/// Returns a negative code if no fallback could be computed: -1 if the target
+/// is not an appropriate `web+` URL ([`FallbackError::NotAnUrl`]), -2 if it is
+/// a valid URL but provides no handler ([`FallbackError::NoHandler`]), and -3
+/// if `uri` was not valid UTF-8.
+#[no_mangle]
+pub unsafe extern "C" fn fc_get_fallback(
+    uri: ConstLtPtr<'_, std::ffi::c_char>,
+    out: MutLtPtr<'_, std::ffi::c_char>,
+    out_len: *mut usize,
+) -> i32 {
+    // SAFETY: guaranteed by API contract.
+    let Ok(uri) = unsafe { CStr::from_lt_ptr(uri) }.to_str() else {
+        return -3
+    };
+
+    let fallback = match get_fallback(uri) {
+        Ok(fallback) => fallback.url,
+        Err(FallbackError::NotAnUrl) => return -1,
+        Err(FallbackError::NoHandler) => return -2,
+    };
+
+    // room for the string plus its trailing NUL.
+    let needed = fallback.len() + 1;
+    // SAFETY: guaranteed by API contract.
+    let cap = unsafe { *out_len };
+    // SAFETY: guaranteed by API contract.
+    unsafe { *out_len = needed };
+    if cap < needed {
+        // caller should allocate `needed` bytes and try again.
+        return 0;
+    }
+
+    // SAFETY: we just checked the buffer is large enough, and the API contract
+    // guarantees `out` points to at least `cap` writable bytes.
+    unsafe {
+        let out = out.as_mut_ptr();
+        std::ptr::copy_nonoverlapping(fallback.as_ptr().cast(), out, fallback.len());
+        *out.add(fallback.len()) = 0;
+    }
+    1
+}
+
+fn fc_open_uri_inner(uri: &str, fallback: &str) -> Result<(), Box<dyn std::error::Error>> {
     let uri = From::from(uri);
     let uri = Uri::CreateUri(&uri)?;
     let fallback = From::from(fallback);
@@ -48,6 +170,140 @@ fn fc_open_uri_inner(uri: &str) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Issues the pre-flight GET used by [`fc_open_uri_checked`], returning whether
+/// the target site answers the fallback request like a deployed handler would.
+async fn handler_is_present(
+    fallback: &Fallback,
+    timeout_ms: u64,
+    max_redirects: u32,
+) -> Result<bool, reqwest::Error> {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(max_redirects as usize))
+        .timeout(std::time::Duration::from_millis(timeout_ms))
+        .build()?;
+    // set the Host header from the parsed authority so virtual-hosted instances
+    // resolve correctly.
+    let response = client
+        .get(&fallback.url)
+        .header(reqwest::header::HOST, &fallback.authority)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Ok(false);
+    }
+    // a handler that is actually deployed answers with a body; an empty 2xx is
+    // treated as "not present".
+    Ok(!response.bytes().await?.is_empty())
+}
+
+// handler scheme/authority filtering
+
+/// A compiled set of glob rules restricting which `web+` handlers may be
+/// dispatched, matched against both the bare scheme and the resolved authority.
+struct HandlerFilter {
+    set: globset::GlobSet,
+    /// When true, a handler is dispatched only if it matches a rule; when
+    /// false, a handler is dispatched unless it matches a rule.
+    default_deny: bool,
+}
+
+impl HandlerFilter {
+    /// Returns whether a handler with the given `scheme` (e.g. `example`) and
+    /// `authority` (e.g. `social.example.org:443`) is allowed to be dispatched.
+    ///
+    /// A rule matches (OR) against any of: the bare scheme, the resolved
+    /// authority (`host:port`), the bare host (so a natural `*.example.org`
+    /// rule matches without the operator having to spell out the port), or the
+    /// combined `scheme@authority` / `scheme@host` forms. The combined forms are
+    /// what let an allowlist tie a scheme *to* an instance — matching `scheme`
+    /// alone would allow it on every host, and the host/authority alone would
+    /// allow every scheme on that host.
+    fn allows(&self, scheme: &str, authority: &str) -> bool {
+        // authority is `host:port`; strip the port to also expose the bare host.
+        let host = authority.rsplit_once(':').map_or(authority, |(host, _)| host);
+        let matched = self.set.is_match(scheme)
+            || self.set.is_match(authority)
+            || self.set.is_match(host)
+            || self.set.is_match(&format!("{}@{}", scheme, authority))
+            || self.set.is_match(&format!("{}@{}", scheme, host));
+        if self.default_deny { matched } else { !matched }
+    }
+}
+
+/// Installs the filter consulted by [`fc_open_uri`] before dispatching a URI.
+///
+/// `patterns` is a newline-separated list of [`globset`] glob rules, matched
+/// against the bare `web+` scheme (e.g. `example`), the resolved authority
+/// (e.g. `social.example.org:443`), the bare host (e.g. `social.example.org`,
+/// so `*.example.org`-style rules match without spelling out the port), or the
+/// combined `scheme@authority` / `scheme@host` forms (e.g.
+/// `example@social.example.org:443`) to tie a scheme to an instance.
+/// When `default_deny` is nonzero
+/// only URIs matching a rule are dispatched; otherwise matching URIs are the
+/// ones blocked. An empty pattern list is kept as-is: in `default_deny` mode an
+/// empty allowlist denies *everything*. Use [`fc_clear_handler_filter`] to
+/// remove the filter entirely.
+///
+/// The C string must be UTF-8, and must not be a null pointer. Returns 1 on
+/// success, 0 if the patterns are not valid UTF-8 or fail to compile.
+#[no_mangle]
+pub unsafe extern "C" fn fc_set_handler_filter(
+    patterns: ConstLtPtr<'_, std::ffi::c_char>,
+    default_deny: i32,
+) -> i32 {
+    // SAFETY: guaranteed by API contract.
+    let Ok(patterns) = unsafe { CStr::from_lt_ptr(patterns) }.to_str() else {
+        return 0
+    };
+
+    match build_handler_filter(patterns, default_deny != 0) {
+        Ok(filter) => {
+            *HANDLER_FILTER.write().unwrap() = Some(filter);
+            1
+        },
+        Err(_) => 0,
+    }
+}
+
+/// Removes any filter installed via [`fc_set_handler_filter`], returning
+/// [`fc_open_uri`] to its default of dispatching every `web+` handler.
+#[no_mangle]
+pub extern "C" fn fc_clear_handler_filter() {
+    *HANDLER_FILTER.write().unwrap() = None;
+}
+
+/// Compiles the newline-separated glob `patterns` into a [`HandlerFilter`].
+///
+/// Blank lines are ignored. An otherwise empty rule set is preserved (it simply
+/// never matches), so an empty allowlist denies everything rather than being
+/// silently treated as "no filter".
+fn build_handler_filter(
+    patterns: &str,
+    default_deny: bool,
+) -> Result<HandlerFilter, globset::Error> {
+    let mut builder = globset::GlobSetBuilder::new();
+    for line in patterns.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        builder.add(globset::Glob::new(line)?);
+    }
+    Ok(HandlerFilter {
+        set: builder.build()?,
+        default_deny,
+    })
+}
+
+/// Checks the given scheme/authority against the installed [`HandlerFilter`],
+/// allowing everything when no filter is set.
+fn handler_is_allowed(scheme: &str, authority: &str) -> bool {
+    match &*HANDLER_FILTER.read().unwrap() {
+        Some(filter) => filter.allows(scheme, authority),
+        None => true,
+    }
+}
+
 // Fedi-To get_fallback implementation
 
 const COMPONENT: &'static AsciiSet = &{
@@ -91,6 +347,17 @@ impl std::fmt::Display for FallbackError {
     }
 }
 
+/// A computed fallback protocol-handler request, along with the scheme and
+/// authority it was derived from so callers can filter on them.
+struct Fallback {
+    /// The `/.well-known/protocol-handler?target=…` URL to launch.
+    url: String,
+    /// The bare `web+` scheme, e.g. `example`.
+    scheme: String,
+    /// The resolved authority (`host:port`), e.g. `social.example.org:443`.
+    authority: String,
+}
+
 /// Checks whether the `scheme` part of `web+scheme` satisfies the desired
 /// constraints.
 fn is_scheme_invalid(scheme: &str) -> bool {
@@ -103,10 +370,11 @@ fn is_scheme_invalid(scheme: &str) -> bool {
 
 /// Attempts to find a fallback protocol handler for the given target URL.
 ///
-/// The target is assumed to be normalized, as per the WHATWG URL spec. (Note
-/// that Fedi-To doesn't actually check that it is, but that's a Fedi-To
-/// issue.)
-fn get_fallback(target: &str) -> Result<String, FallbackError> {
+/// The target is normalized as per the WHATWG URL spec before being embedded
+/// into the fallback request, so that the same logical link always produces
+/// the same handler request regardless of the casing or IDNA form the caller
+/// happened to pass in.
+fn get_fallback(target: &str) -> Result<Fallback, FallbackError> {
     use FallbackError::*;
     // find the scheme
     let scheme = {
@@ -145,12 +413,146 @@ fn get_fallback(target: &str) -> Result<String, FallbackError> {
     // NOTE: this is the same URL parser as used by browsers when handling
     // `href` so this is correct.
     let mut url = url::Url::parse(&*as_if_https).map_err(|_| NoHandler)?;
+    // Rebuild a canonical target from the normalized URL record. The parser has
+    // already IDNA/punycode-encoded the host, lowercased the scheme, elided the
+    // default port and percent-encoded the path and query per the WHATWG sets;
+    // the record also still carries the fragment, which we want on the target.
+    // All we have to do is swap the leading `https` back for the original
+    // `web+scheme`. Because we only reach this point with an authority present,
+    // this never fabricates a `//` for an authority-less `web+foo:bar` target.
+    let target = {
+        let normalized = url.as_str();
+        // `normalized` is guaranteed to start with "https" here.
+        let mut target = String::with_capacity(
+            "web+".len() + scheme.len() + (normalized.len() - "https".len()),
+        );
+        target.push_str("web+");
+        target.push_str(scheme);
+        target.push_str(&normalized["https".len()..]);
+        target
+    };
+    // record the scheme/authority so callers can filter on them. the host is
+    // always present here (we rejected the authority-less cases above), and for
+    // `https` the port is always known.
+    let authority = match url.port_or_known_default() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+        None => url.host_str().unwrap_or("").to_owned(),
+    };
+    let fallback_scheme = scheme.to_owned();
     url.set_path("/.well-known/protocol-handler");
     let _ = url.set_username("");
     let _ = url.set_password(None);
     let mut params = "target=".to_owned();
-    params.extend(utf8_percent_encode(&*target, COMPONENT));
+    params.extend(utf8_percent_encode(&target, COMPONENT));
     url.set_query(Some(&*params));
     url.set_fragment(None);
-    Ok(url.into())
+    Ok(Fallback {
+        url: url.into(),
+        scheme: fallback_scheme,
+        authority,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_handler_filter, get_fallback, FallbackError};
+
+    #[test]
+    fn empty_allowlist_denies_everything() {
+        let filter = build_handler_filter("", true).unwrap();
+        assert!(!filter.allows("example", "social.example.org:443"));
+    }
+
+    #[test]
+    fn empty_denylist_allows_everything() {
+        let filter = build_handler_filter("", false).unwrap();
+        assert!(filter.allows("example", "social.example.org:443"));
+    }
+
+    #[test]
+    fn bare_scheme_rule_matches_any_host() {
+        let filter = build_handler_filter("example", true).unwrap();
+        assert!(filter.allows("example", "social.example.org:443"));
+        assert!(filter.allows("example", "other.test:443"));
+        assert!(!filter.allows("other", "social.example.org:443"));
+    }
+
+    #[test]
+    fn combined_rule_ties_scheme_to_instance() {
+        let filter = build_handler_filter("example@social.example.org:443", true).unwrap();
+        assert!(filter.allows("example", "social.example.org:443"));
+        // the same scheme on a different host is not allowed...
+        assert!(!filter.allows("example", "other.test:443"));
+        // ...nor a different scheme on the same host.
+        assert!(!filter.allows("other", "social.example.org:443"));
+    }
+
+    #[test]
+    fn host_rule_matches_without_explicit_port() {
+        let filter = build_handler_filter("*.example.org", true).unwrap();
+        assert!(filter.allows("example", "social.example.org:443"));
+    }
+
+    #[test]
+    fn normalizes_host_to_idna_and_lowercases() {
+        let fallback = get_fallback("web+example://WWW.Montréal.com/Path").unwrap();
+        assert_eq!(fallback.scheme, "example");
+        assert_eq!(fallback.authority, "www.xn--montral-fya.com:443");
+        // host is IDNA-encoded and lowercased in both the request and the target,
+        // while the path case is preserved.
+        assert_eq!(
+            fallback.url,
+            "https://www.xn--montral-fya.com/.well-known/protocol-handler\
+             ?target=web%2Bexample%3A%2F%2Fwww.xn--montral-fya.com%2FPath",
+        );
+    }
+
+    #[test]
+    fn elides_default_port() {
+        let fallback = get_fallback("web+example://host.example:443/x").unwrap();
+        assert_eq!(fallback.authority, "host.example:443");
+        assert!(fallback.url.starts_with("https://host.example/.well-known/"));
+        assert!(!fallback.url.contains("host.example:443"));
+    }
+
+    #[test]
+    fn reattaches_fragment_to_target_but_strips_it_from_request() {
+        let fallback = get_fallback("web+example://host.example/p#frag").unwrap();
+        // the fragment survives, percent-encoded, inside the target parameter...
+        assert!(fallback.url.contains("%23frag"));
+        // ...but the outgoing request itself carries no fragment.
+        assert!(!fallback.url.contains('#'));
+    }
+
+    #[test]
+    fn rejects_authority_less_target() {
+        assert!(matches!(
+            get_fallback("web+example:bar"),
+            Err(FallbackError::NoHandler),
+        ));
+    }
+
+    #[test]
+    fn rejects_empty_authority() {
+        assert!(matches!(
+            get_fallback("web+example:///x"),
+            Err(FallbackError::NoHandler),
+        ));
+        assert!(matches!(
+            get_fallback(r"web+example://\"),
+            Err(FallbackError::NoHandler),
+        ));
+    }
+
+    #[test]
+    fn rejects_non_web_scheme() {
+        assert!(matches!(
+            get_fallback("https://example.com"),
+            Err(FallbackError::NotAnUrl),
+        ));
+        assert!(matches!(
+            get_fallback("not a url"),
+            Err(FallbackError::NotAnUrl),
+        ));
+    }
 }